@@ -0,0 +1,240 @@
+use super::message::*;
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+
+pub struct ConversationStore {
+    conn: Connection,
+}
+
+impl ConversationStore {
+    pub fn open(path: &std::path::Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open conversation store at {}", path.display()))?;
+        Self::from_connection(conn)
+    }
+
+    #[cfg(test)]
+    fn open_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                conversation_id TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                role TEXT NOT NULL,
+                content TEXT NOT NULL,
+                model TEXT,
+                rendered_prompt TEXT,
+                completion_id TEXT,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (conversation_id, seq)
+            )",
+        )?;
+        Ok(Self { conn })
+    }
+
+    pub fn append(&self, conversation_id: &str, message: &Message) -> Result<()> {
+        self.append_row(conversation_id, message, None, None, None)
+    }
+
+    pub fn append_exchange(
+        &self,
+        conversation_id: &str,
+        message: &Message,
+        model: &str,
+        rendered_prompt: &str,
+        completion_id: Option<&str>,
+    ) -> Result<()> {
+        self.append_row(
+            conversation_id,
+            message,
+            Some(model),
+            Some(rendered_prompt),
+            completion_id,
+        )
+    }
+
+    fn append_row(
+        &self,
+        conversation_id: &str,
+        message: &Message,
+        model: Option<&str>,
+        rendered_prompt: Option<&str>,
+        completion_id: Option<&str>,
+    ) -> Result<()> {
+        let seq = self.next_seq(conversation_id)?;
+        let role = role_name(&message.role);
+        let content = serde_json::to_string(&message.content)?;
+        self.conn.execute(
+            "INSERT INTO messages
+                (conversation_id, seq, role, content, model, rendered_prompt, completion_id, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, strftime('%s', 'now'))",
+            params![
+                conversation_id,
+                seq,
+                role,
+                content,
+                model,
+                rendered_prompt,
+                completion_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn next_seq(&self, conversation_id: &str) -> Result<i64> {
+        let seq: Option<i64> = self.conn.query_row(
+            "SELECT MAX(seq) FROM messages WHERE conversation_id = ?1",
+            params![conversation_id],
+            |row| row.get(0),
+        )?;
+        Ok(seq.map(|seq| seq + 1).unwrap_or(0))
+    }
+
+    pub fn load(&self, conversation_id: &str) -> Result<Vec<Message>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT role, content FROM messages WHERE conversation_id = ?1 ORDER BY seq ASC",
+        )?;
+        let messages = stmt
+            .query_map(params![conversation_id], |row| {
+                let role: String = row.get(0)?;
+                let content: String = row.get(1)?;
+                Ok((role, content))
+            })?
+            .map(|row| -> Result<Message> {
+                let (role, content) = row?;
+                Ok(Message {
+                    role: role_from_name(&role),
+                    content: serde_json::from_str(&content)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(messages)
+    }
+
+    pub fn list_conversations(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT conversation_id FROM messages ORDER BY conversation_id ASC")?;
+        let ids = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(ids)
+    }
+}
+
+fn role_name(role: &MessageRole) -> &'static str {
+    match role {
+        MessageRole::System => "system",
+        MessageRole::User => "user",
+        MessageRole::Assistant => "assistant",
+    }
+}
+
+fn role_from_name(role: &str) -> MessageRole {
+    match role {
+        "system" => MessageRole::System,
+        "assistant" => MessageRole::Assistant,
+        _ => MessageRole::User,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_message(role: MessageRole, text: &str) -> Message {
+        Message {
+            role,
+            content: MessageContent::Text(text.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_append_and_load_round_trip() {
+        let store = ConversationStore::open_in_memory().unwrap();
+        store
+            .append("conv-1", &text_message(MessageRole::User, "Hello"))
+            .unwrap();
+        store
+            .append("conv-1", &text_message(MessageRole::Assistant, "Hi there"))
+            .unwrap();
+
+        let messages = store.load("conv-1").unwrap();
+        assert_eq!(messages, vec![
+            text_message(MessageRole::User, "Hello"),
+            text_message(MessageRole::Assistant, "Hi there"),
+        ]);
+    }
+
+    #[test]
+    fn test_load_keeps_conversations_separate() {
+        let store = ConversationStore::open_in_memory().unwrap();
+        store
+            .append("conv-1", &text_message(MessageRole::User, "From conv 1"))
+            .unwrap();
+        store
+            .append("conv-2", &text_message(MessageRole::User, "From conv 2"))
+            .unwrap();
+
+        assert_eq!(
+            store.load("conv-1").unwrap(),
+            vec![text_message(MessageRole::User, "From conv 1")]
+        );
+        assert_eq!(
+            store.load("conv-2").unwrap(),
+            vec![text_message(MessageRole::User, "From conv 2")]
+        );
+    }
+
+    #[test]
+    fn test_list_conversations() {
+        let store = ConversationStore::open_in_memory().unwrap();
+        store
+            .append("conv-b", &text_message(MessageRole::User, "Hi"))
+            .unwrap();
+        store
+            .append("conv-a", &text_message(MessageRole::User, "Hi"))
+            .unwrap();
+
+        assert_eq!(
+            store.list_conversations().unwrap(),
+            vec!["conv-a".to_string(), "conv-b".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_append_exchange_stores_model_prompt_and_completion_id() {
+        let store = ConversationStore::open_in_memory().unwrap();
+        store
+            .append_exchange(
+                "conv-1",
+                &text_message(MessageRole::Assistant, "Hi there"),
+                "claude-3",
+                "\n\nHuman: Hello\n\nAssistant:",
+                Some("cmpl-123"),
+            )
+            .unwrap();
+
+        let model: String = store
+            .conn
+            .query_row("SELECT model FROM messages WHERE conversation_id = 'conv-1'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        let completion_id: String = store
+            .conn
+            .query_row(
+                "SELECT completion_id FROM messages WHERE conversation_id = 'conv-1'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(model, "claude-3");
+        assert_eq!(completion_id, "cmpl-123");
+    }
+}
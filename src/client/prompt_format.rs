@@ -1,5 +1,8 @@
 use super::message::*;
 
+use minijinja::value::Value as JinjaValue;
+use minijinja::{context, Environment, Error as JinjaError, ErrorKind};
+
 pub struct PromptFormat<'a> {
     pub begin: &'a str,
     pub system_pre_message: &'a str,
@@ -9,6 +12,8 @@ pub struct PromptFormat<'a> {
     pub assistant_pre_message: &'a str,
     pub assistant_post_message: &'a str,
     pub end: &'a str,
+    pub alternation_policy: AlternationPolicy,
+    pub image_placeholder: Option<&'a str>,
 }
 
 pub const GENERIC_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
@@ -20,6 +25,8 @@ pub const GENERIC_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
     assistant_pre_message: "### Response:\n",
     assistant_post_message: "\n",
     end: "### Response:\n",
+    alternation_policy: AlternationPolicy::Permissive,
+    image_placeholder: None,
 };
 
 pub const ANTHROPIC_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
@@ -31,6 +38,8 @@ pub const ANTHROPIC_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
     assistant_pre_message: "\nAssistant: ",
     assistant_post_message: "\n",
     end: "\nAssistant:",
+    alternation_policy: AlternationPolicy::Permissive,
+    image_placeholder: None,
 };
 
 pub const MISTRAL_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
@@ -42,6 +51,8 @@ pub const MISTRAL_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
     assistant_pre_message: "",
     assistant_post_message: "",
     end: "",
+    alternation_policy: AlternationPolicy::Strict,
+    image_placeholder: None,
 };
 
 pub const LLAMA3_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
@@ -53,6 +64,8 @@ pub const LLAMA3_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
     assistant_pre_message: "<|start_header_id|>assistant<|end_header_id|>\n\n",
     assistant_post_message: "<|eot_id|>",
     end: "<|start_header_id|>assistant<|end_header_id|>\n\n",
+    alternation_policy: AlternationPolicy::Permissive,
+    image_placeholder: None,
 };
 
 pub const PHI3_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
@@ -64,6 +77,8 @@ pub const PHI3_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
     assistant_pre_message: "<|assistant|>\n",
     assistant_post_message: "<|end|>\n",
     end: "<|assistant|>\n",
+    alternation_policy: AlternationPolicy::Permissive,
+    image_placeholder: None,
 };
 
 pub const COMMAND_R_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
@@ -75,6 +90,8 @@ pub const COMMAND_R_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
     assistant_pre_message: "<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>",
     assistant_post_message: "<|END_OF_TURN_TOKEN|>",
     end: "<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>",
+    alternation_policy: AlternationPolicy::Permissive,
+    image_placeholder: None,
 };
 
 pub const QWEN_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
@@ -86,9 +103,120 @@ pub const QWEN_PROMPT_FORMAT: PromptFormat<'static> = PromptFormat {
     assistant_pre_message: "<|im_start|>assistant\n",
     assistant_post_message: "<|im_end|>",
     end: "<|im_start|>assistant\n",
+    alternation_policy: AlternationPolicy::Permissive,
+    image_placeholder: None,
 };
 
-pub fn generate_prompt(messages: &[Message], format: PromptFormat) -> anyhow::Result<String> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlternationPolicy {
+    Permissive,
+    Strict,
+    Merge,
+}
+
+pub fn normalize_messages(
+    messages: &[Message],
+    policy: AlternationPolicy,
+) -> anyhow::Result<Vec<Message>> {
+    if policy == AlternationPolicy::Permissive {
+        return Ok(messages.to_vec());
+    }
+
+    let mut stray_system = vec![];
+    let mut rest: Vec<Message> = vec![];
+    for (index, message) in messages.iter().enumerate() {
+        if index > 0 && message.role == MessageRole::System {
+            match policy {
+                AlternationPolicy::Strict => anyhow::bail!(
+                    "Conversation roles must alternate user/assistant/user/assistant/...; system message at index {index} must be first"
+                ),
+                AlternationPolicy::Merge => stray_system.push(message.content.clone()),
+                AlternationPolicy::Permissive => unreachable!(),
+            }
+        } else {
+            rest.push(message.clone());
+        }
+    }
+
+    if !stray_system.is_empty() {
+        let first_user = rest
+            .iter_mut()
+            .find(|message| message.role == MessageRole::User)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Cannot fold a stray system message: no user message present")
+            })?;
+        let mut folded = stray_system.remove(0);
+        for content in stray_system {
+            folded = merge_content(&folded, &content);
+        }
+        first_user.content = merge_content(&folded, &first_user.content);
+    }
+
+    let mut normalized: Vec<Message> = vec![];
+    for (index, message) in rest.into_iter().enumerate() {
+        match normalized.last_mut() {
+            Some(previous) if previous.role == message.role => match policy {
+                AlternationPolicy::Strict => anyhow::bail!(
+                    "Conversation roles must alternate user/assistant/user/assistant/...; message at index {index} repeats the role of the previous message"
+                ),
+                AlternationPolicy::Merge => {
+                    previous.content = merge_content(&previous.content, &message.content);
+                }
+                AlternationPolicy::Permissive => unreachable!(),
+            },
+            _ => normalized.push(message),
+        }
+    }
+
+    Ok(normalized)
+}
+
+fn message_text(content: &MessageContent) -> String {
+    match content {
+        MessageContent::Text(text) => text.clone(),
+        MessageContent::Array(list) => list
+            .iter()
+            .filter_map(|item| match item {
+                MessageContentPart::Text { text } => Some(text.clone()),
+                MessageContentPart::ImageUrl { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        MessageContent::ToolResults(_) => String::new(),
+    }
+}
+
+fn contains_image(content: &MessageContent) -> bool {
+    matches!(content, MessageContent::Array(list) if list.iter().any(|item| matches!(item, MessageContentPart::ImageUrl { .. })))
+}
+
+fn message_parts(content: &MessageContent) -> Vec<MessageContentPart> {
+    match content {
+        MessageContent::Text(text) => vec![MessageContentPart::Text { text: text.clone() }],
+        MessageContent::Array(list) => list.clone(),
+        MessageContent::ToolResults(_) => vec![],
+    }
+}
+
+fn merge_content(first: &MessageContent, second: &MessageContent) -> MessageContent {
+    if !contains_image(first) && !contains_image(second) {
+        let mut merged = message_text(first);
+        merged.push_str("\n\n");
+        merged.push_str(&message_text(second));
+        return MessageContent::Text(merged);
+    }
+    let mut parts = message_parts(first);
+    parts.extend(message_parts(second));
+    MessageContent::Array(parts)
+}
+
+pub struct PromptOutput {
+    pub text: String,
+    pub images: Vec<String>,
+}
+
+pub fn generate_prompt(messages: &[Message], format: PromptFormat) -> anyhow::Result<PromptOutput> {
+    let messages = normalize_messages(messages, format.alternation_policy)?;
     let PromptFormat {
         begin,
         system_pre_message,
@@ -98,10 +226,12 @@ pub fn generate_prompt(messages: &[Message], format: PromptFormat) -> anyhow::Re
         assistant_pre_message,
         assistant_post_message,
         end,
+        alternation_policy: _,
+        image_placeholder,
     } = format;
     let mut prompt = begin.to_string();
-    let mut image_urls = vec![];
-    for message in messages {
+    let mut images = vec![];
+    for message in &messages {
         let role = &message.role;
         let content = match &message.content {
             MessageContent::Text(text) => text.clone(),
@@ -113,7 +243,10 @@ pub fn generate_prompt(messages: &[Message], format: PromptFormat) -> anyhow::Re
                         MessageContentPart::ImageUrl {
                             image_url: ImageUrl { url },
                         } => {
-                            image_urls.push(url.clone());
+                            if let Some(placeholder) = image_placeholder {
+                                parts.push(placeholder.to_string());
+                            }
+                            images.push(url.clone());
                         }
                     }
                 }
@@ -133,13 +266,131 @@ pub fn generate_prompt(messages: &[Message], format: PromptFormat) -> anyhow::Re
             }
         }
     }
-    if !image_urls.is_empty() {
-        anyhow::bail!("The model does not support images: {:?}", image_urls);
+    if image_placeholder.is_none() && !images.is_empty() {
+        anyhow::bail!("The model does not support images: {:?}", images);
     }
     prompt.push_str(end);
+    Ok(PromptOutput {
+        text: prompt,
+        images,
+    })
+}
+
+pub struct JinjaPromptFormat {
+    pub template: String,
+    pub bos_token: String,
+    pub eos_token: String,
+}
+
+pub fn generate_prompt_jinja(
+    messages: &[Message],
+    format: &JinjaPromptFormat,
+) -> anyhow::Result<String> {
+    let mut env = Environment::new();
+    env.add_function("raise_exception", raise_exception);
+    env.add_template("chat", &format.template)?;
+    let template = env.get_template("chat")?;
+
+    let messages: Vec<JinjaValue> = messages
+        .iter()
+        .map(|message| {
+            let role = match message.role {
+                MessageRole::System => "system",
+                MessageRole::Assistant => "assistant",
+                MessageRole::User => "user",
+            };
+            let content = message_text(&message.content);
+            context! { role, content }
+        })
+        .collect();
+
+    let prompt = template.render(context! {
+        messages,
+        bos_token => &format.bos_token,
+        eos_token => &format.eos_token,
+        add_generation_prompt => true,
+    })?;
+
     Ok(prompt)
 }
 
+fn raise_exception(msg: String) -> Result<String, JinjaError> {
+    Err(JinjaError::new(ErrorKind::InvalidOperation, msg))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FimOrder {
+    Psm,
+    Spm,
+}
+
+pub struct FimFormat<'a> {
+    pub begin: &'a str,
+    pub prefix_token: &'a str,
+    pub suffix_token: &'a str,
+    pub middle_token: &'a str,
+    pub end: &'a str,
+    pub order: FimOrder,
+}
+
+pub const CODELLAMA_FIM_FORMAT: FimFormat<'static> = FimFormat {
+    begin: "",
+    prefix_token: "<PRE> ",
+    suffix_token: "<SUF> ",
+    middle_token: "<MID> ",
+    end: "",
+    order: FimOrder::Psm,
+};
+
+pub const DEEPSEEK_FIM_FORMAT: FimFormat<'static> = FimFormat {
+    begin: "",
+    prefix_token: "<｜fim▁begin｜>",
+    suffix_token: "<｜fim▁hole｜>",
+    middle_token: "<｜fim▁end｜>",
+    end: "",
+    order: FimOrder::Psm,
+};
+
+pub const MISTRAL_FIM_FORMAT: FimFormat<'static> = FimFormat {
+    begin: "<s>",
+    prefix_token: "[PREFIX]",
+    suffix_token: "[SUFFIX]",
+    middle_token: "",
+    end: "",
+    order: FimOrder::Spm,
+};
+
+pub fn generate_fim_prompt(prefix: &str, suffix: &str, format: &FimFormat) -> anyhow::Result<String> {
+    let FimFormat {
+        begin,
+        prefix_token,
+        suffix_token,
+        middle_token,
+        end,
+        order,
+    } = format;
+    let body = match order {
+        FimOrder::Psm => format!("{prefix_token}{prefix}{suffix_token}{suffix}{middle_token}"),
+        FimOrder::Spm => format!("{suffix_token}{suffix}{prefix_token}{prefix}{middle_token}"),
+    };
+    Ok(format!("{begin}{body}{end}"))
+}
+
+pub fn smart_fim_format(model_name: &str) -> FimFormat<'static> {
+    if model_name.contains("codellama")
+        || model_name.contains("code-llama")
+        || model_name.contains("starcoder")
+    {
+        CODELLAMA_FIM_FORMAT
+    } else if model_name.contains("deepseek") {
+        DEEPSEEK_FIM_FORMAT
+    } else if model_name.contains("mistral") {
+        MISTRAL_FIM_FORMAT
+    } else {
+        CODELLAMA_FIM_FORMAT
+    }
+}
+
 pub fn smart_prompt_format(model_name: &str) -> PromptFormat<'static> {
     if model_name.contains("llama3") || model_name.contains("llama-3") {
         LLAMA3_PROMPT_FORMAT
@@ -196,7 +447,8 @@ Can you explain quantum computing?
 ### Response:
 ";
 
-        assert_eq!(result, expected);
+        assert_eq!(result.text, expected);
+        assert!(result.images.is_empty());
     }
 
     #[test]
@@ -223,6 +475,234 @@ Can you explain quantum computing?
 \nHuman: And what's the capital of Italy?
 \nAssistant: ";
 
+        assert_eq!(result.text, expected);
+    }
+
+    #[test]
+    fn test_generate_prompt_jinja() {
+        let messages = vec![
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hello, how are you?".to_string()),
+            },
+            Message {
+                role: MessageRole::Assistant,
+                content: MessageContent::Text("I'm doing well, thank you!".to_string()),
+            },
+        ];
+
+        let format = JinjaPromptFormat {
+            template: "{{ bos_token }}{% for message in messages %}{{ message['role'] }}: {{ message['content'] }}\n{% endfor %}{% if add_generation_prompt %}assistant:\n{% endif %}".to_string(),
+            bos_token: "<s>".to_string(),
+            eos_token: "</s>".to_string(),
+        };
+
+        let result = generate_prompt_jinja(&messages, &format).unwrap();
+        let expected = "\
+<s>user: Hello, how are you?
+assistant: I'm doing well, thank you!
+assistant:
+";
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_generate_prompt_jinja_raise_exception() {
+        let messages = vec![
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi".to_string()),
+            },
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi again".to_string()),
+            },
+        ];
+
+        let format = JinjaPromptFormat {
+            template: "{% if messages[0]['role'] == messages[1]['role'] %}{{ raise_exception('Conversation roles must alternate') }}{% endif %}".to_string(),
+            bos_token: "<s>".to_string(),
+            eos_token: "</s>".to_string(),
+        };
+
+        let result = generate_prompt_jinja(&messages, &format);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_normalize_messages_strict_rejects_repeated_role() {
+        let messages = vec![
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi".to_string()),
+            },
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi again".to_string()),
+            },
+        ];
+
+        let result = normalize_messages(&messages, AlternationPolicy::Strict);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_normalize_messages_merge_combines_repeated_role() {
+        let messages = vec![
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi".to_string()),
+            },
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi again".to_string()),
+            },
+        ];
+
+        let result = normalize_messages(&messages, AlternationPolicy::Merge).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(
+            result[0].content,
+            MessageContent::Text("Hi\n\nHi again".to_string())
+        );
+    }
+
+    #[test]
+    fn test_normalize_messages_merge_preserves_image() {
+        let messages = vec![
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Array(vec![MessageContentPart::ImageUrl {
+                    image_url: ImageUrl {
+                        url: "https://example.com/cat.png".to_string(),
+                    },
+                }]),
+            },
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("What is in this picture?".to_string()),
+            },
+        ];
+
+        let result = normalize_messages(&messages, AlternationPolicy::Merge).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(
+            result[0].content,
+            MessageContent::Array(vec![
+                MessageContentPart::ImageUrl {
+                    image_url: ImageUrl {
+                        url: "https://example.com/cat.png".to_string(),
+                    },
+                },
+                MessageContentPart::Text {
+                    text: "What is in this picture?".to_string(),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_generate_prompt_with_mistral_format_rejects_repeated_role() {
+        let messages = vec![
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi".to_string()),
+            },
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi again".to_string()),
+            },
+        ];
+
+        let result = generate_prompt(&messages, MISTRAL_PROMPT_FORMAT);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_generate_prompt_without_placeholder_bails_on_images() {
+        let messages = vec![Message {
+            role: MessageRole::User,
+            content: MessageContent::Array(vec![MessageContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: "https://example.com/cat.png".to_string(),
+                },
+            }]),
+        }];
+
+        let result = generate_prompt(&messages, GENERIC_PROMPT_FORMAT);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_generate_prompt_with_placeholder_splices_image_token() {
+        let format = PromptFormat {
+            image_placeholder: Some("<image>"),
+            ..GENERIC_PROMPT_FORMAT
+        };
+        let messages = vec![Message {
+            role: MessageRole::User,
+            content: MessageContent::Array(vec![
+                MessageContentPart::Text {
+                    text: "What is in this picture?".to_string(),
+                },
+                MessageContentPart::ImageUrl {
+                    image_url: ImageUrl {
+                        url: "https://example.com/cat.png".to_string(),
+                    },
+                },
+            ]),
+        }];
+
+        let result = generate_prompt(&messages, format).unwrap();
+        let expected = "### Instruction:\nWhat is in this picture?\n\n<image>\n### Response:\n";
+
+        assert_eq!(result.text, expected);
+        assert_eq!(result.images, vec!["https://example.com/cat.png".to_string()]);
+    }
+
+    #[test]
+    fn test_generate_fim_prompt_psm() {
+        let result =
+            generate_fim_prompt("def fib(n):\n    ", "\n    return fib(n - 1) + fib(n - 2)", &CODELLAMA_FIM_FORMAT)
+                .unwrap();
+        let expected =
+            "<PRE> def fib(n):\n    <SUF> \n    return fib(n - 1) + fib(n - 2)<MID> ";
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_generate_fim_prompt_deepseek_is_prefix_first() {
+        let result = generate_fim_prompt("def fib(n):\n    ", "\n    return result", &DEEPSEEK_FIM_FORMAT).unwrap();
+        let expected =
+            "<｜fim▁begin｜>def fib(n):\n    <｜fim▁hole｜>\n    return result<｜fim▁end｜>";
+
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_generate_fim_prompt_spm() {
+        let format = FimFormat {
+            order: FimOrder::Spm,
+            ..CODELLAMA_FIM_FORMAT
+        };
+        let result = generate_fim_prompt("def fib(n):\n    ", "\n    return result", &format).unwrap();
+        let expected = "<SUF> \n    return result<PRE> def fib(n):\n    <MID> ";
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_smart_fim_format_routes_by_model_name() {
+        assert_eq!(smart_fim_format("codellama-7b").order, FimOrder::Psm);
+        assert_eq!(smart_fim_format("starcoder2-15b").order, FimOrder::Psm);
+        assert_eq!(smart_fim_format("deepseek-coder-v2").order, FimOrder::Psm);
+        assert_eq!(smart_fim_format("mistral-7b").order, FimOrder::Spm);
+        assert_eq!(
+            smart_fim_format("mistral-7b").prefix_token,
+            MISTRAL_FIM_FORMAT.prefix_token
+        );
+        assert_eq!(smart_fim_format("gpt-4").order, FimOrder::Psm);
+    }
 }
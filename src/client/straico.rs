@@ -1,3 +1,4 @@
+use super::conversation_store::ConversationStore;
 use super::prompt_format::*;
 use super::*;
 
@@ -18,6 +19,30 @@ pub struct StraicoConfig {
     pub extra: Option<ExtraConfig>,
 }
 
+pub fn straico_record_exchange(
+    store: &ConversationStore,
+    conversation_id: &str,
+    messages: &[Message],
+    model: &Model,
+    output: &ChatCompletionsOutput,
+) -> Result<()> {
+    let prompt = generate_prompt(messages, smart_prompt_format(model.name()))?;
+    if let Some(outgoing) = messages.last() {
+        store.append_exchange(conversation_id, outgoing, model.name(), &prompt.text, None)?;
+    }
+    let reply = Message {
+        role: MessageRole::Assistant,
+        content: MessageContent::Text(output.text.clone()),
+    };
+    store.append_exchange(
+        conversation_id,
+        &reply,
+        model.name(),
+        &prompt.text,
+        output.id.as_deref(),
+    )
+}
+
 impl StraicoClient {
     config_get_fn!(api_key, get_api_key);
 
@@ -84,7 +109,7 @@ fn straico_build_chat_completions_body(data: ChatCompletionsData, model: &Model)
     let prompt = generate_prompt(&messages, smart_prompt_format(model.name()))?;
 
     Ok(json!({
-        "message": prompt,
+        "message": prompt.text,
         "models": [model.name()],
     }))
 